@@ -0,0 +1,160 @@
+//! FastCDC content-defined chunking.
+//!
+//! Cut points are derived from the file bytes themselves (a rolling "gear"
+//! hash) rather than from fixed offsets, so two files that share a run of
+//! bytes also share chunk boundaries around that run. `ConexPlanner` uses the
+//! resulting chunk hashes to dedupe identical regions across files and
+//! layers instead of re-shipping them.
+
+/// 256-entry gear table used to feed the rolling hash. Values are derived
+/// from a fixed seed via splitmix64 so the table is reproducible without
+/// checking in 2KB of magic numbers.
+pub const GEAR: [u64; 256] = build_gear_table();
+
+const fn splitmix64(seed: u64) -> (u64, u64) {
+    let seed = seed.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    (z ^ (z >> 31), seed)
+}
+
+const fn build_gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut seed = 0x2545F4914F6CDD1Du64;
+    let mut i = 0;
+    while i < 256 {
+        let (value, next_seed) = splitmix64(seed);
+        table[i] = value;
+        seed = next_seed;
+        i += 1;
+    }
+    table
+}
+
+/// Size bounds and target ("normal") size for the chunker.
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkerConfig {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl Default for ChunkerConfig {
+    fn default() -> Self {
+        Self {
+            min_size: 2 * 1024 * 1024,
+            avg_size: 8 * 1024 * 1024,
+            max_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// One content-defined chunk within a file, along with the strong hash used
+/// to detect duplicates across files and layers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub start_offset: usize,
+    pub chunk_size: usize,
+    pub hash: blake3::Hash,
+}
+
+/// Split `data` into content-defined chunks using FastCDC.
+///
+/// A cut point is declared once `min_size` bytes have accumulated and the
+/// rolling gear hash's low bits are all zero under `mask_large` (before
+/// `avg_size` bytes, making an early cut less likely) or `mask_small` (after,
+/// making a cut more likely so the chunk doesn't run too far past the
+/// target). `max_size` is a hard ceiling so a pathological run of bytes can't
+/// produce an unbounded chunk.
+pub fn fastcdc_chunks(data: &[u8], cfg: &ChunkerConfig) -> Vec<Chunk> {
+    let mask_small = normalized_mask(cfg.avg_size, -2);
+    let mask_large = normalized_mask(cfg.avg_size, 2);
+
+    let mut chunks = Vec::new();
+    let mut start = 0usize;
+    while start < data.len() {
+        let remaining = data.len() - start;
+        if remaining <= cfg.min_size {
+            chunks.push(make_chunk(data, start, remaining));
+            break;
+        }
+
+        let mut h: u64 = 0;
+        let mut offset = cfg.min_size;
+        let mut cut = cfg.max_size.min(remaining);
+        let mut found = false;
+        while offset < remaining.min(cfg.max_size) {
+            let byte = data[start + offset];
+            h = (h << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if offset < cfg.avg_size { mask_large } else { mask_small };
+            if h & mask == 0 {
+                cut = offset;
+                found = true;
+                break;
+            }
+            offset += 1;
+        }
+        let _ = found;
+        chunks.push(make_chunk(data, start, cut));
+        start += cut;
+    }
+    chunks
+}
+
+fn make_chunk(data: &[u8], start: usize, size: usize) -> Chunk {
+    Chunk {
+        start_offset: start,
+        chunk_size: size,
+        hash: blake3::hash(&data[start..start + size]),
+    }
+}
+
+/// Build a mask with `(log2(avg_size) + shift)` low bits set to zero-checked,
+/// matching the FastCDC "normalization level" trick: a negative shift yields
+/// a smaller mask (fewer bits to zero-check, so a cut is more likely), a
+/// positive shift yields a larger mask (more bits, so a cut is less likely).
+/// Callers apply the larger mask before the target size and the smaller mask
+/// after, biasing cuts toward `avg_size` instead of drifting early or late.
+fn normalized_mask(avg_size: usize, shift: i32) -> u64 {
+    let bits = (usize::BITS - avg_size.leading_zeros()).saturating_sub(1) as i32;
+    let bits = (bits + shift).clamp(1, 63) as u32;
+    (1u64 << bits) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn respects_min_and_max_size() {
+        let cfg = ChunkerConfig { min_size: 16, avg_size: 64, max_size: 128 };
+        let data = vec![0u8; 1000];
+        let chunks = fastcdc_chunks(&data, &cfg);
+        for chunk in &chunks {
+            assert!(chunk.chunk_size <= cfg.max_size);
+        }
+        // Only the final chunk may fall short of `min_size`.
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.chunk_size >= cfg.min_size);
+        }
+        let total: usize = chunks.iter().map(|c| c.chunk_size).sum();
+        assert_eq!(total, data.len());
+    }
+
+    #[test]
+    fn identical_regions_produce_identical_chunk_hashes() {
+        let cfg = ChunkerConfig { min_size: 8, avg_size: 32, max_size: 64 };
+        let mut data = vec![1u8; 200];
+        data.extend(vec![2u8; 200]);
+        let mut other = vec![9u8; 50];
+        other.extend(vec![1u8; 200]);
+        other.extend(vec![2u8; 200]);
+
+        let a = fastcdc_chunks(&data, &cfg);
+        let b = fastcdc_chunks(&other, &cfg);
+        let a_hashes: std::collections::HashSet<_> = a.iter().map(|c| c.hash).collect();
+        let b_hashes: std::collections::HashSet<_> = b.iter().map(|c| c.hash).collect();
+        assert!(a_hashes.intersection(&b_hashes).next().is_some());
+    }
+}
@@ -0,0 +1,403 @@
+//! Binary on-disk format ("docket") for a generated plan.
+//!
+//! A docket is a small fixed header (magic, version, layer count) followed
+//! by an offset table of per-layer record offsets, followed by tightly
+//! packed variable-width `ConexFile` records. The offset table lets a single
+//! file be parsed straight from its offset instead of deserializing the
+//! whole docket up front. The data region is read via `mmap` for fast random
+//! access, except on NFS (mmap over NFS is unsound), where we fall back to a
+//! plain buffered read.
+
+use std::ffi::CString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use crate::planner::{ConexChunk, ConexFile, FileKind};
+
+const MAGIC: [u8; 6] = *b"DOCKET";
+const VERSION: u16 = 1;
+const NONE_SENTINEL: i64 = -1;
+
+/// `statfs.f_type` magic for NFS, per `statfs(2)`.
+const NFS_SUPER_MAGIC: i64 = 0x6969;
+
+#[derive(Debug)]
+pub enum DocketError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+}
+
+impl fmt::Display for DocketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DocketError::Io(err) => write!(f, "{err}"),
+            DocketError::BadMagic => write!(f, "not a docket file"),
+            DocketError::UnsupportedVersion(v) => write!(f, "unsupported docket version {v}"),
+        }
+    }
+}
+
+impl std::error::Error for DocketError {}
+
+impl From<io::Error> for DocketError {
+    fn from(err: io::Error) -> Self {
+        DocketError::Io(err)
+    }
+}
+
+/// Write `plan` to `path` as a docket.
+pub fn write_plan(plan: &[(String, Vec<ConexFile>)], path: &Path) -> Result<(), DocketError> {
+    // Serialize every record first so we know each record's absolute offset
+    // once the header + offset table length is known. `hard_link_to` is
+    // stored as an index into the same layer's file list rather than a path.
+    let mut layer_records: Vec<Vec<Vec<u8>>> = Vec::with_capacity(plan.len());
+    for (_, files) in plan {
+        let index_by_path: std::collections::HashMap<&Path, i64> = files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.relative_path.as_path(), i as i64))
+            .collect();
+        layer_records.push(
+            files
+                .iter()
+                .map(|file| {
+                    let hard_link_index = file
+                        .hard_link_to
+                        .as_deref()
+                        .and_then(|target| index_by_path.get(target))
+                        .copied()
+                        .unwrap_or(NONE_SENTINEL);
+                    encode_record(file, hard_link_index)
+                })
+                .collect(),
+        );
+    }
+
+    // Offset table entry: name_len(4) + name + file_count(4) + offsets(8 each).
+    let mut index_len = 0usize;
+    for ((name, _), records) in plan.iter().zip(layer_records.iter()) {
+        index_len += 4 + name.len() + 4 + 8 * records.len();
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(plan.len() as u32).to_le_bytes());
+
+    let data_start = 6 + 2 + 4 + index_len;
+    let mut offsets_per_layer = Vec::with_capacity(plan.len());
+    let mut running = data_start;
+    for records in &layer_records {
+        let mut offsets = Vec::with_capacity(records.len());
+        for record in records {
+            offsets.push(running as u64);
+            running += record.len();
+        }
+        offsets_per_layer.push(offsets);
+    }
+
+    for (((name, _), records), offsets) in plan.iter().zip(layer_records.iter()).zip(offsets_per_layer.iter()) {
+        out.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(&(records.len() as u32).to_le_bytes());
+        for offset in offsets {
+            out.extend_from_slice(&offset.to_le_bytes());
+        }
+    }
+
+    for records in &layer_records {
+        for record in records {
+            out.extend_from_slice(record);
+        }
+    }
+
+    let mut file = File::create(path)?;
+    file.write_all(&out)?;
+    Ok(())
+}
+
+/// Read a docket previously written by `write_plan` back into a plan.
+pub fn read_plan(path: &Path) -> Result<Vec<(String, Vec<ConexFile>)>, DocketError> {
+    let data = load_docket_bytes(path)?;
+    if data.len() < 12 || data[0..6] != MAGIC {
+        return Err(DocketError::BadMagic);
+    }
+    let version = u16::from_le_bytes(data[6..8].try_into().unwrap());
+    if version != VERSION {
+        return Err(DocketError::UnsupportedVersion(version));
+    }
+    let layer_count = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+
+    let mut cursor = 12usize;
+    let mut plan = Vec::with_capacity(layer_count);
+    for _ in 0..layer_count {
+        let name_len = read_u32(&data, &mut cursor) as usize;
+        let name = String::from_utf8_lossy(&data[cursor..cursor + name_len]).into_owned();
+        cursor += name_len;
+        let file_count = read_u32(&data, &mut cursor) as usize;
+        let mut record_offsets = Vec::with_capacity(file_count);
+        for _ in 0..file_count {
+            record_offsets.push(read_u64(&data, &mut cursor) as usize);
+        }
+
+        let decoded: Vec<(ConexFile, i64)> = record_offsets
+            .iter()
+            .map(|&offset| decode_record(&data, offset))
+            .collect();
+        let paths: Vec<PathBuf> = decoded.iter().map(|(f, _)| f.relative_path.clone()).collect();
+        let files = decoded
+            .into_iter()
+            .map(|(mut file, hard_link_index)| {
+                if hard_link_index != NONE_SENTINEL {
+                    file.hard_link_to = paths.get(hard_link_index as usize).cloned();
+                }
+                file
+            })
+            .collect();
+        plan.push((name, files));
+    }
+    Ok(plan)
+}
+
+/// `record_offsets` from a docket's index let a single file be decoded
+/// straight from its offset, without walking every earlier record in the
+/// layer; `read_plan` above simply does that for every offset in turn.
+///
+/// Decode the record at `cursor`, returning the file plus its raw
+/// `hard_link_to` index (relative to the enclosing layer) for the caller to
+/// resolve once every record in the layer has been decoded.
+fn decode_record(data: &[u8], mut cursor: usize) -> (ConexFile, i64) {
+    let relative_path_len = read_u32(data, &mut cursor) as usize;
+    let relative_path_bytes = data[cursor..cursor + relative_path_len].to_vec();
+    cursor += relative_path_len;
+    let relative_path = PathBuf::from(std::ffi::OsString::from_vec(relative_path_bytes));
+
+    let path_len = read_u32(data, &mut cursor) as usize;
+    let path_bytes = data[cursor..cursor + path_len].to_vec();
+    cursor += path_len;
+    let path = PathBuf::from(std::ffi::OsString::from_vec(path_bytes));
+
+    let size = read_u64(data, &mut cursor) as usize;
+    let inode = read_u64(data, &mut cursor);
+    let ctime_nsec = read_i64(data, &mut cursor);
+    let start_offset = read_i64(data, &mut cursor);
+    let chunk_size = read_i64(data, &mut cursor);
+    let hard_link_to_index = read_i64(data, &mut cursor);
+    let kind = decode_kind(data, &mut cursor);
+    let xattrs = decode_xattrs(data, &mut cursor);
+    let content_chunks = decode_content_chunks(data, &mut cursor);
+
+    let file = ConexFile {
+        path,
+        relative_path,
+        size,
+        inode,
+        hard_link_to: None,
+        ctime_nsec,
+        start_offset: (start_offset != NONE_SENTINEL).then_some(start_offset as usize),
+        chunk_size: (chunk_size != NONE_SENTINEL).then_some(chunk_size as usize),
+        content_chunks,
+        kind,
+        xattrs,
+    };
+    (file, hard_link_to_index)
+}
+
+fn encode_record(file: &ConexFile, hard_link_index: i64) -> Vec<u8> {
+    let mut out = Vec::new();
+    let relative_path_bytes = file.relative_path.as_os_str().as_bytes();
+    out.extend_from_slice(&(relative_path_bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(relative_path_bytes);
+    // `path` is the absolute path `chunk_file`/an upload reads bytes from;
+    // persisted alongside (not aliased onto) `relative_path` so a plan
+    // loaded from a docket can still locate file contents.
+    let path_bytes = file.path.as_os_str().as_bytes();
+    out.extend_from_slice(&(path_bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(path_bytes);
+    out.extend_from_slice(&(file.size as u64).to_le_bytes());
+    out.extend_from_slice(&file.inode.to_le_bytes());
+    out.extend_from_slice(&file.ctime_nsec.to_le_bytes());
+    out.extend_from_slice(&file.start_offset.map_or(NONE_SENTINEL, |v| v as i64).to_le_bytes());
+    out.extend_from_slice(&file.chunk_size.map_or(NONE_SENTINEL, |v| v as i64).to_le_bytes());
+    out.extend_from_slice(&hard_link_index.to_le_bytes());
+    encode_kind(&file.kind, &mut out);
+    encode_xattrs(&file.xattrs, &mut out);
+    encode_content_chunks(&file.content_chunks, &mut out);
+    out
+}
+
+/// `FileKind` tag byte, matching `decode_kind`: 0 Regular, 1 Symlink,
+/// 2 BlockDev, 3 CharDev, 4 Fifo, 5 Dir.
+fn encode_kind(kind: &FileKind, out: &mut Vec<u8>) {
+    match kind {
+        FileKind::Regular => out.push(0),
+        FileKind::Symlink { target } => {
+            out.push(1);
+            let bytes = target.as_os_str().as_bytes();
+            out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+            out.extend_from_slice(bytes);
+        }
+        FileKind::BlockDev { rdev } => {
+            out.push(2);
+            out.extend_from_slice(&rdev.to_le_bytes());
+        }
+        FileKind::CharDev { rdev } => {
+            out.push(3);
+            out.extend_from_slice(&rdev.to_le_bytes());
+        }
+        FileKind::Fifo => out.push(4),
+        FileKind::Dir => out.push(5),
+    }
+}
+
+fn decode_kind(data: &[u8], cursor: &mut usize) -> FileKind {
+    let tag = data[*cursor];
+    *cursor += 1;
+    match tag {
+        1 => {
+            let len = read_u32(data, cursor) as usize;
+            let target = PathBuf::from(std::ffi::OsString::from_vec(data[*cursor..*cursor + len].to_vec()));
+            *cursor += len;
+            FileKind::Symlink { target }
+        }
+        2 => FileKind::BlockDev { rdev: read_u64(data, cursor) },
+        3 => FileKind::CharDev { rdev: read_u64(data, cursor) },
+        4 => FileKind::Fifo,
+        5 => FileKind::Dir,
+        _ => FileKind::Regular,
+    }
+}
+
+fn encode_xattrs(xattrs: &[(std::ffi::OsString, Vec<u8>)], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(xattrs.len() as u32).to_le_bytes());
+    for (name, value) in xattrs {
+        let name_bytes = name.as_os_str().as_bytes();
+        out.extend_from_slice(&(name_bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(name_bytes);
+        out.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        out.extend_from_slice(value);
+    }
+}
+
+fn decode_xattrs(data: &[u8], cursor: &mut usize) -> Vec<(std::ffi::OsString, Vec<u8>)> {
+    let count = read_u32(data, cursor) as usize;
+    (0..count)
+        .map(|_| {
+            let name_len = read_u32(data, cursor) as usize;
+            let name = std::ffi::OsString::from_vec(data[*cursor..*cursor + name_len].to_vec());
+            *cursor += name_len;
+            let value_len = read_u32(data, cursor) as usize;
+            let value = data[*cursor..*cursor + value_len].to_vec();
+            *cursor += value_len;
+            (name, value)
+        })
+        .collect()
+}
+
+fn encode_content_chunks(chunks: &Option<Vec<ConexChunk>>, out: &mut Vec<u8>) {
+    match chunks {
+        None => out.push(0),
+        Some(chunks) => {
+            out.push(1);
+            out.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+            for chunk in chunks {
+                out.extend_from_slice(&(chunk.start_offset as u64).to_le_bytes());
+                out.extend_from_slice(&(chunk.chunk_size as u64).to_le_bytes());
+                out.extend_from_slice(chunk.hash.as_bytes());
+                out.push(chunk.is_duplicate as u8);
+            }
+        }
+    }
+}
+
+fn decode_content_chunks(data: &[u8], cursor: &mut usize) -> Option<Vec<ConexChunk>> {
+    let has_chunks = data[*cursor];
+    *cursor += 1;
+    if has_chunks == 0 {
+        return None;
+    }
+    let count = read_u32(data, cursor) as usize;
+    Some(
+        (0..count)
+            .map(|_| {
+                let start_offset = read_u64(data, cursor) as usize;
+                let chunk_size = read_u64(data, cursor) as usize;
+                let mut hash_bytes = [0u8; 32];
+                hash_bytes.copy_from_slice(&data[*cursor..*cursor + 32]);
+                *cursor += 32;
+                let is_duplicate = data[*cursor] != 0;
+                *cursor += 1;
+                ConexChunk { start_offset, chunk_size, hash: blake3::Hash::from(hash_bytes), is_duplicate }
+            })
+            .collect(),
+    )
+}
+
+fn read_u32(data: &[u8], cursor: &mut usize) -> u32 {
+    let v = u32::from_le_bytes(data[*cursor..*cursor + 4].try_into().unwrap());
+    *cursor += 4;
+    v
+}
+
+fn read_u64(data: &[u8], cursor: &mut usize) -> u64 {
+    let v = u64::from_le_bytes(data[*cursor..*cursor + 8].try_into().unwrap());
+    *cursor += 8;
+    v
+}
+
+fn read_i64(data: &[u8], cursor: &mut usize) -> i64 {
+    let v = i64::from_le_bytes(data[*cursor..*cursor + 8].try_into().unwrap());
+    *cursor += 8;
+    v
+}
+
+/// Load the docket's bytes via `mmap` for random access, unless `path` sits
+/// on an NFS mount, where mmap can silently corrupt reads on writeback
+/// errors; there we fall back to a plain buffered read.
+fn load_docket_bytes(path: &Path) -> io::Result<Vec<u8>> {
+    if is_nfs(path)? {
+        return fs::read(path);
+    }
+
+    let file = File::open(path)?;
+    let len = file.metadata()?.len() as usize;
+    if len == 0 {
+        return Ok(Vec::new());
+    }
+
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return fs::read(path);
+    }
+
+    let bytes = unsafe { std::slice::from_raw_parts(ptr as *const u8, len) }.to_vec();
+    unsafe {
+        libc::munmap(ptr, len);
+    }
+    Ok(bytes)
+}
+
+fn is_nfs(path: &Path) -> io::Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path has interior NUL"))?;
+    let mut stats: libc::statfs = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::statfs(c_path.as_ptr(), &mut stats) };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stats.f_type as i64 == NFS_SUPER_MAGIC)
+}
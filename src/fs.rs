@@ -0,0 +1,159 @@
+//! Filesystem access behind a trait so `ConexPlanner::ingest_dir` can be
+//! driven from an in-memory fixture in tests instead of a real tree like
+//! `/var/lib/docker`.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+/// What kind of directory entry `symlink_metadata` found, independent of any
+/// particular `Fs` backend.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryType {
+    Regular,
+    Dir,
+    Symlink,
+    BlockDev,
+    CharDev,
+    Fifo,
+}
+
+/// The subset of `std::fs::Metadata` `ingest_dir` needs, re-exposed so
+/// `FakeFs` can produce it without a backing inode.
+#[derive(Clone, Debug)]
+pub struct EntryMetadata {
+    pub entry_type: EntryType,
+    pub len: u64,
+    pub ino: u64,
+    pub rdev: u64,
+    pub ctime_nsec: i64,
+}
+
+/// One entry returned from `Fs::read_dir`.
+#[derive(Clone, Debug)]
+pub struct DirEntryInfo {
+    pub path: PathBuf,
+}
+
+/// Filesystem surface `ingest_dir` walks. `RealFs` backs it with
+/// `std::fs`; `FakeFs` backs it with an in-memory tree for tests.
+pub trait Fs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntryInfo>>;
+    fn symlink_metadata(&self, path: &Path) -> io::Result<EntryMetadata>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+/// `Fs` backed by the host filesystem.
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntryInfo>> {
+        fs::read_dir(path)?
+            .map(|entry| Ok(DirEntryInfo { path: entry?.path() }))
+            .collect()
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<EntryMetadata> {
+        let metadata = fs::symlink_metadata(path)?;
+        let file_type = metadata.file_type();
+        let entry_type = if file_type.is_symlink() {
+            EntryType::Symlink
+        } else if file_type.is_dir() {
+            EntryType::Dir
+        } else if file_type.is_block_device() {
+            EntryType::BlockDev
+        } else if file_type.is_char_device() {
+            EntryType::CharDev
+        } else if file_type.is_fifo() {
+            EntryType::Fifo
+        } else {
+            EntryType::Regular
+        };
+        Ok(EntryMetadata {
+            entry_type,
+            len: metadata.len(),
+            ino: metadata.ino(),
+            rdev: metadata.rdev(),
+            ctime_nsec: metadata.ctime_nsec(),
+        })
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+}
+
+/// A single in-memory directory entry for `FakeFs`.
+#[derive(Clone, Debug)]
+pub struct FakeEntry {
+    pub entry_type: EntryType,
+    pub len: u64,
+    pub ino: u64,
+    pub rdev: u64,
+    pub ctime_nsec: i64,
+    pub symlink_target: Option<PathBuf>,
+}
+
+impl FakeEntry {
+    pub fn dir() -> Self {
+        Self { entry_type: EntryType::Dir, len: 0, ino: 0, rdev: 0, ctime_nsec: 0, symlink_target: None }
+    }
+
+    pub fn regular(ino: u64, len: u64) -> Self {
+        Self { entry_type: EntryType::Regular, len, ino, rdev: 0, ctime_nsec: 0, symlink_target: None }
+    }
+
+    pub fn symlink(target: impl Into<PathBuf>) -> Self {
+        Self {
+            entry_type: EntryType::Symlink,
+            len: 0,
+            ino: 0,
+            rdev: 0,
+            ctime_nsec: 0,
+            symlink_target: Some(target.into()),
+        }
+    }
+}
+
+/// An `Fs` backed by a `BTreeMap` of path to entry, used to drive
+/// `ingest_dir` over a synthetic tree in unit tests.
+#[derive(Default)]
+pub struct FakeFs {
+    pub entries: BTreeMap<PathBuf, FakeEntry>,
+}
+
+impl Fs for FakeFs {
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntryInfo>> {
+        let mut children: Vec<PathBuf> = self
+            .entries
+            .keys()
+            .filter(|candidate| candidate.parent() == Some(path))
+            .cloned()
+            .collect();
+        children.sort();
+        Ok(children.into_iter().map(|path| DirEntryInfo { path }).collect())
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<EntryMetadata> {
+        let entry = self
+            .entries
+            .get(path)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such fake entry"))?;
+        Ok(EntryMetadata {
+            entry_type: entry.entry_type,
+            len: entry.len,
+            ino: entry.ino,
+            rdev: entry.rdev,
+            ctime_nsec: entry.ctime_nsec,
+        })
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.entries
+            .get(path)
+            .and_then(|entry| entry.symlink_target.clone())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a fake symlink"))
+    }
+}
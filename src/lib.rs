@@ -0,0 +1,5 @@
+pub mod chunker;
+pub mod docket;
+pub mod fs;
+pub mod planner;
+pub mod stats;
@@ -1,16 +1,58 @@
-use core::panic;
 use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::{self};
-use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
 use std::cmp;
 
+use crate::chunker::{self, ChunkerConfig};
+use crate::fs::{DirEntryInfo, EntryType, Fs, RealFs};
 
 pub struct ConexPlanner {
     pub layer_to_files: Vec<(String, Vec<ConexFile>)>,
     pub split_threshold: usize,
+    /// When set, `generate_plan` content-defined-chunks regular files (see
+    /// `crate::chunker`) and dedupes chunks against `seen_chunk_hashes`
+    /// instead of only hard-linking identical inodes.
+    pub chunking: Option<ChunkerConfig>,
+    seen_chunk_hashes: HashSet<blake3::Hash>,
+    fs: Box<dyn Fs>,
+    pub pack_strategy: PackStrategy,
 }
 
+/// Layer bin-packing strategy for Pass 2 of `generate_plan`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PackStrategy {
+    /// Fill the currently open layer in ingest order. A file with
+    /// `hard_link_to` set that would overflow `split_threshold` is split at
+    /// the boundary; any other file is added whole.
+    #[default]
+    Greedy,
+    /// Sort whole files largest-first and place each into the open layer
+    /// with the most remaining capacity that still fits it.
+    BestFitDecreasing,
+}
+
+/// Error returned by `ConexPlanner::ingest_dir` in place of the panics it
+/// used to raise on a missing/inaccessible path or a failed directory read.
+#[derive(Debug)]
+pub enum IngestError {
+    NotADirectory(PathBuf),
+    Io(PathBuf, std::io::Error),
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::NotADirectory(path) => write!(f, "{} is not a directory", path.display()),
+            IngestError::Io(path, err) => write!(f, "{}: {err}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for IngestError {}
+
 #[derive(Clone, Debug)]
 pub struct ConexFile {
     pub path: PathBuf,
@@ -21,33 +63,92 @@ pub struct ConexFile {
     pub ctime_nsec: i64,
     pub start_offset: Option<usize>,
     pub chunk_size: Option<usize>,
+    /// Content-defined chunks for this file once `ConexPlanner::chunking` is
+    /// enabled; `None` means the file was planned with fixed-offset
+    /// splitting only.
+    pub content_chunks: Option<Vec<ConexChunk>>,
+    pub kind: FileKind,
+    pub xattrs: Vec<(OsString, Vec<u8>)>,
+}
+
+/// What an entry is on disk, beyond "how many bytes". `generate_plan` keeps
+/// non-regular entries at zero length (they're never split) but still places
+/// them in layer order so symlinks, device nodes, and FIFOs survive a
+/// round-trip through a plan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Regular,
+    Symlink { target: PathBuf },
+    BlockDev { rdev: u64 },
+    CharDev { rdev: u64 },
+    Fifo,
+    Dir,
+}
+
+/// One content-defined chunk of a `ConexFile`, annotated with whether its
+/// hash had already been seen elsewhere in the plan.
+#[derive(Clone, Debug)]
+pub struct ConexChunk {
+    pub start_offset: usize,
+    pub chunk_size: usize,
+    pub hash: blake3::Hash,
+    pub is_duplicate: bool,
 }
 
 impl ConexPlanner {
     pub fn default() -> Self {
+        Self::with_fs(Box::new(RealFs))
+    }
+
+    /// Build a planner over a custom `Fs`, e.g. `FakeFs` in tests.
+    pub fn with_fs(fs: Box<dyn Fs>) -> Self {
         Self {
             layer_to_files: Vec::new(),
             split_threshold: 512 * 1024 * 1024,
+            chunking: None,
+            seen_chunk_hashes: HashSet::new(),
+            fs,
+            pack_strategy: PackStrategy::default(),
         }
     }
 
-    pub fn ingest_dir(&mut self, dir_path: &str) {
-        let base_path = PathBuf::from(dir_path.clone());
-
-        if base_path.metadata().is_err()
-            && base_path.metadata().err().unwrap().kind() == std::io::ErrorKind::PermissionDenied
-        {
-            panic!(
-                "Path is not accessible.
-            Run `sudo setfacl -m u:ubuntu:rx /var /var/lib /var/lib/docker`
-            and `sudo setfacl -R -m u:ubuntu:rx /var /var/lib /var/lib/docker/overlay2`
-            "
-            );
-        }
+    /// Content-defined-chunk `file` in place, recording which chunks are
+    /// new payload versus duplicates of a chunk already seen in this plan.
+    /// Takes `seen` explicitly (rather than `&mut self`) so callers can hold
+    /// a mutable borrow of `layer_to_files` at the same time.
+    fn chunk_file(
+        file: &mut ConexFile,
+        cfg: &ChunkerConfig,
+        seen: &mut HashSet<blake3::Hash>,
+    ) -> std::io::Result<()> {
+        let data = fs::read(&file.path)?;
+        let chunks = chunker::fastcdc_chunks(&data, cfg);
+        file.content_chunks = Some(
+            chunks
+                .into_iter()
+                .map(|chunk| {
+                    let is_duplicate = !seen.insert(chunk.hash);
+                    ConexChunk {
+                        start_offset: chunk.start_offset,
+                        chunk_size: chunk.chunk_size,
+                        hash: chunk.hash,
+                        is_duplicate,
+                    }
+                })
+                .collect(),
+        );
+        Ok(())
+    }
 
-        if !base_path.is_dir() {
-            // TODO: change to log fatal.
-            panic!("Path is not a directory");
+    pub fn ingest_dir(&mut self, dir_path: &str) -> Result<(), IngestError> {
+        let base_path = PathBuf::from(dir_path);
+
+        match self.fs.symlink_metadata(&base_path) {
+            Ok(metadata) if metadata.entry_type != EntryType::Dir => {
+                return Err(IngestError::NotADirectory(base_path));
+            }
+            Err(err) => return Err(IngestError::Io(base_path, err)),
+            Ok(_) => {}
         }
 
         let mut queue = VecDeque::new();
@@ -57,31 +158,55 @@ impl ConexPlanner {
 
         while let Some(current_path) = queue.pop_front() {
             let absolute_path = base_path.join(&current_path);
-            for entry in fs::read_dir(&absolute_path).unwrap() {
-                let entry = entry.unwrap();
-                // let metadata = entry.metadata().unwrap();
-                let metadata = std::fs::symlink_metadata(entry.path()).unwrap();
-                let relative_path = entry.path().strip_prefix(&base_path).unwrap().to_path_buf();
-
-                if entry.path().is_dir() && !metadata.is_symlink() {
+            let entries: Vec<DirEntryInfo> = self
+                .fs
+                .read_dir(&absolute_path)
+                .map_err(|err| IngestError::Io(absolute_path.clone(), err))?;
+
+            for entry in entries {
+                let metadata = self
+                    .fs
+                    .symlink_metadata(&entry.path)
+                    .map_err(|err| IngestError::Io(entry.path.clone(), err))?;
+                let relative_path = entry.path.strip_prefix(&base_path).unwrap().to_path_buf();
+
+                if metadata.entry_type == EntryType::Dir {
                     queue.push_back(relative_path.to_owned());
                 }
 
+                let kind = match metadata.entry_type {
+                    EntryType::Symlink => FileKind::Symlink {
+                        target: self.fs.read_link(&entry.path).unwrap_or_default(),
+                    },
+                    EntryType::Dir => FileKind::Dir,
+                    EntryType::BlockDev => FileKind::BlockDev { rdev: metadata.rdev },
+                    EntryType::CharDev => FileKind::CharDev { rdev: metadata.rdev },
+                    EntryType::Fifo => FileKind::Fifo,
+                    EntryType::Regular => FileKind::Regular,
+                };
+                // Non-regular entries carry no split-able payload: keep them
+                // zero-length so Pass 2 never fragments a symlink or device node.
+                let size = if kind == FileKind::Regular { metadata.len as usize } else { 0 };
+
                 file_metadata_vec.push(ConexFile {
-                    path: entry.path(),
+                    path: entry.path.clone(),
                     relative_path,
-                    size: metadata.len() as usize,
-                    inode: metadata.ino(),
+                    size,
+                    inode: metadata.ino,
                     hard_link_to: None,
-                    ctime_nsec: metadata.ctime_nsec(),
+                    ctime_nsec: metadata.ctime_nsec,
                     start_offset: None,
-                    chunk_size: None
+                    chunk_size: None,
+                    content_chunks: None,
+                    xattrs: read_xattrs(&entry.path),
+                    kind,
                 });
             }
         }
 
         self.layer_to_files
             .push((dir_path.to_owned(), file_metadata_vec));
+        Ok(())
     }
 
     pub fn generate_plan(mut self) -> Vec<(String, Vec<ConexFile>)> {
@@ -104,12 +229,73 @@ impl ConexPlanner {
             })
             .collect::<Vec<(String, Vec<ConexFile>)>>();
 
+        // Pass 1.5: content-define chunks for non-hard-linked regular files
+        // so identical regions across files/layers dedupe by chunk hash.
+        if let Some(cfg) = self.chunking {
+            for (_, files) in self.layer_to_files.iter_mut() {
+                for file in files.iter_mut() {
+                    if file.hard_link_to.is_none() && file.kind == FileKind::Regular {
+                        // Best-effort: a file that disappeared or became
+                        // unreadable between ingest and planning just keeps
+                        // its fixed-offset fragmentation.
+                        let _ = Self::chunk_file(file, &cfg, &mut self.seen_chunk_hashes);
+                    }
+                }
+            }
+        }
+
         // Pass 2: Split and collapse layers so the size is about 512MB.
+        match self.pack_strategy {
+            PackStrategy::Greedy => self.pack_greedy(),
+            PackStrategy::BestFitDecreasing => self.pack_best_fit_decreasing(),
+        }
+    }
+
+    /// Single-pass greedy fill: files are placed in ingest order into the
+    /// currently open layer. A file with `hard_link_to` set that would
+    /// overflow `split_threshold` is split at the boundary and the layer is
+    /// closed; a file without `hard_link_to` is added whole and never closes
+    /// the layer, even when it takes the layer past `split_threshold`.
+    fn pack_greedy(&self) -> Vec<(String, Vec<ConexFile>)> {
         let mut new_layer_to_files = Vec::new();
         let mut current_layer_size: usize = 0;
         let mut new_layer = Vec::new();
         for (layer, files) in self.layer_to_files.iter() {
             for file in files.iter() {
+                if file.size == 0 {
+                    // Symlinks, device nodes, FIFOs, and empty regular files
+                    // carry no split-able payload but still need a slot in
+                    // layer order, so the `while remainder_size != 0` loop
+                    // below (which never runs for them) can't be the only
+                    // place they're emitted.
+                    new_layer.push(file.clone());
+                    continue;
+                }
+
+                if let Some(chunks) = &file.content_chunks {
+                    // Content-defined chunks already carry their own cut
+                    // points: emit one fragment per chunk instead of
+                    // re-slicing by `split_threshold`, and let a duplicate
+                    // chunk ride as a zero-cost reference (it contributes no
+                    // bytes to the layer budget) instead of a new payload.
+                    for chunk in chunks {
+                        let mut frag = file.clone();
+                        frag.start_offset = Some(chunk.start_offset);
+                        frag.chunk_size = Some(chunk.chunk_size);
+                        frag.content_chunks = Some(vec![chunk.clone()]);
+                        new_layer.push(frag);
+                        if !chunk.is_duplicate {
+                            current_layer_size += chunk.chunk_size;
+                        }
+                        if current_layer_size >= self.split_threshold {
+                            new_layer_to_files.push((layer.clone(), new_layer.clone()));
+                            new_layer = Vec::new();
+                            current_layer_size = 0;
+                        }
+                    }
+                    continue;
+                }
+
                 let mut remainder_size = file.size;
                 while remainder_size != 0 {
                     let mut frag = file.clone();
@@ -131,7 +317,7 @@ impl ConexPlanner {
                         new_layer = Vec::new();
                         current_layer_size = 0;
                         remainder_size -= frag.chunk_size.unwrap();
-                    } 
+                    }
                 }
             }
         }
@@ -143,12 +329,214 @@ impl ConexPlanner {
         //println!("{} layers created from {} layers given, plan len {}",layer_counter,num_layers, new_layer_to_files.len());
         new_layer_to_files.clone()
     }
+
+    /// Best-fit-decreasing: whole files (or, for a content-chunked file,
+    /// each of its chunks individually) are sorted largest-cost-first
+    /// (hard-linked entries last) and
+    /// each placed into whichever open layer has the most remaining
+    /// capacity that still fits it, only opening a new layer when none
+    /// fits. A duplicate chunk costs nothing against a bin's budget, same
+    /// as `pack_greedy`. A whole file larger than `split_threshold` still
+    /// falls back to fixed-offset splitting across fresh layers. Hard-linked
+    /// entries are forced into the same layer as the file they link to, so
+    /// the link reference stays valid.
+    fn pack_best_fit_decreasing(&self) -> Vec<(String, Vec<ConexFile>)> {
+        struct Bin {
+            layer: String,
+            files: Vec<ConexFile>,
+            used: usize,
+        }
+
+        /// One placeable unit: a whole file, or (when the file carries
+        /// `content_chunks`) a single chunk of it. `cost` is what it counts
+        /// against a bin's budget — zero for a duplicate chunk, since it
+        /// rides as a reference instead of new payload.
+        struct Item<'a> {
+            layer: &'a str,
+            file: &'a ConexFile,
+            chunk: Option<&'a ConexChunk>,
+            cost: usize,
+        }
+
+        let mut items: Vec<Item> = Vec::new();
+        for (layer, files) in self.layer_to_files.iter() {
+            for file in files {
+                if let Some(chunks) = &file.content_chunks {
+                    for chunk in chunks {
+                        let cost = if chunk.is_duplicate { 0 } else { chunk.chunk_size };
+                        items.push(Item { layer, file, chunk: Some(chunk), cost });
+                    }
+                } else {
+                    items.push(Item { layer, file, chunk: None, cost: file.size });
+                }
+            }
+        }
+        // Hard-linked entries sort after everything else so the file they
+        // link to (and every chunk of it) already has a bin.
+        items.sort_by_key(|i| (i.file.hard_link_to.is_some(), cmp::Reverse(i.cost)));
+
+        let mut bins: Vec<Bin> = Vec::new();
+        let mut bin_of_path: HashMap<PathBuf, usize> = HashMap::new();
+
+        let materialize = |file: &ConexFile, chunk: Option<&ConexChunk>| -> ConexFile {
+            let mut out = file.clone();
+            if let Some(chunk) = chunk {
+                out.start_offset = Some(chunk.start_offset);
+                out.chunk_size = Some(chunk.chunk_size);
+                out.content_chunks = Some(vec![chunk.clone()]);
+            }
+            out
+        };
+
+        for item in items {
+            let file = item.file;
+
+            if let Some(target) = &file.hard_link_to {
+                if let Some(&bin_idx) = bin_of_path.get(target) {
+                    bins[bin_idx].used += item.cost;
+                    bins[bin_idx].files.push(materialize(file, item.chunk));
+                    bin_of_path.insert(file.relative_path.clone(), bin_idx);
+                    continue;
+                }
+            }
+
+            if item.chunk.is_none() && file.size > self.split_threshold {
+                let mut remainder_size = file.size;
+                while remainder_size != 0 {
+                    let mut frag = file.clone();
+                    let take = remainder_size.min(self.split_threshold);
+                    frag.start_offset = Some(file.size - remainder_size);
+                    frag.chunk_size = Some(take);
+                    bins.push(Bin { layer: item.layer.to_owned(), files: vec![frag], used: take });
+                    remainder_size -= take;
+                }
+                bin_of_path.insert(file.relative_path.clone(), bins.len() - 1);
+                continue;
+            }
+
+            let best_bin = bins
+                .iter()
+                .enumerate()
+                .filter(|(_, bin)| self.split_threshold - bin.used >= item.cost)
+                .max_by_key(|(_, bin)| self.split_threshold - bin.used)
+                .map(|(idx, _)| idx);
+
+            let bin_idx = best_bin.unwrap_or_else(|| {
+                bins.push(Bin { layer: item.layer.to_owned(), files: Vec::new(), used: 0 });
+                bins.len() - 1
+            });
+            bins[bin_idx].used += item.cost;
+            bins[bin_idx].files.push(materialize(file, item.chunk));
+            bin_of_path.insert(file.relative_path.clone(), bin_idx);
+        }
+
+        bins.into_iter().map(|bin| (bin.layer, bin.files)).collect()
+    }
+
+    /// Serialize `self.layer_to_files` to `path` as a docket (see
+    /// `crate::docket`) so an interrupted push can resume without re-walking
+    /// the tree.
+    pub fn write_plan(&self, path: &Path) -> Result<(), crate::docket::DocketError> {
+        crate::docket::write_plan(&self.layer_to_files, path)
+    }
+
+    /// Load a plan previously written by `write_plan`.
+    pub fn read_plan(path: &Path) -> Result<Vec<(String, Vec<ConexFile>)>, crate::docket::DocketError> {
+        crate::docket::read_plan(path)
+    }
+
+    /// Report per-layer and aggregate dedup/packing stats for `plan`, the
+    /// output of `generate_plan`. See `crate::stats`.
+    pub fn stats(plan: &[(String, Vec<ConexFile>)]) -> crate::stats::PlanStats {
+        crate::stats::plan_stats(plan)
+    }
+}
+
+/// Read every extended attribute on `path` without following a trailing
+/// symlink (`llistxattr`/`lgetxattr`), so the xattr set we capture matches
+/// the `symlink_metadata` we already took for the entry.
+fn read_xattrs(path: &Path) -> Vec<(OsString, Vec<u8>)> {
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return Vec::new();
+    };
+
+    let list_size = unsafe { libc::llistxattr(c_path.as_ptr(), std::ptr::null_mut(), 0) };
+    if list_size <= 0 {
+        return Vec::new();
+    }
+    let mut list_buf = vec![0u8; list_size as usize];
+    let list_size = unsafe {
+        libc::llistxattr(c_path.as_ptr(), list_buf.as_mut_ptr() as *mut libc::c_char, list_buf.len())
+    };
+    if list_size <= 0 {
+        return Vec::new();
+    }
+    list_buf.truncate(list_size as usize);
+
+    list_buf
+        .split(|&b| b == 0)
+        .filter(|name| !name.is_empty())
+        .filter_map(|name| {
+            let Ok(c_name) = std::ffi::CString::new(name) else {
+                return None;
+            };
+            let value_size = unsafe { libc::lgetxattr(c_path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+            if value_size < 0 {
+                return None;
+            }
+            let mut value_buf = vec![0u8; value_size as usize];
+            let value_size = unsafe {
+                libc::lgetxattr(
+                    c_path.as_ptr(),
+                    c_name.as_ptr(),
+                    value_buf.as_mut_ptr() as *mut libc::c_void,
+                    value_buf.len(),
+                )
+            };
+            if value_size < 0 {
+                return None;
+            }
+            value_buf.truncate(value_size as usize);
+            Some((OsString::from_vec(name.to_vec()), value_buf))
+        })
+        .collect()
 }
 
 // unit test module
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fs::{FakeEntry, FakeFs};
+
+    /// Exercises `ingest_dir` itself (directory walk, recursion into a
+    /// subdirectory, hard-link detection across same-inode files) over a
+    /// `FakeFs` tree, without touching the host filesystem.
+    #[test]
+    fn ingest_dir_over_fake_fs_detects_hard_links() {
+        let mut fake = FakeFs::default();
+        fake.entries.insert(PathBuf::from("/src"), FakeEntry::dir());
+        fake.entries.insert(PathBuf::from("/src/a.txt"), FakeEntry::regular(10, 4));
+        // Same inode as a.txt: a hard link.
+        fake.entries.insert(PathBuf::from("/src/b.txt"), FakeEntry::regular(10, 4));
+        fake.entries.insert(PathBuf::from("/src/sub"), FakeEntry::dir());
+        fake.entries.insert(PathBuf::from("/src/sub/c.txt"), FakeEntry::regular(20, 8));
+
+        let mut planner = ConexPlanner::with_fs(Box::new(fake));
+        planner.ingest_dir("/src").unwrap();
+
+        let plan = planner.generate_plan();
+        let files: Vec<&ConexFile> = plan.iter().flat_map(|(_, files)| files.iter()).collect();
+
+        let a = files.iter().find(|f| f.relative_path == Path::new("a.txt")).unwrap();
+        let b = files.iter().find(|f| f.relative_path == Path::new("b.txt")).unwrap();
+        let c = files.iter().find(|f| f.relative_path == Path::new("sub/c.txt")).unwrap();
+
+        assert_eq!(a.hard_link_to, None);
+        assert_eq!(b.hard_link_to, Some(PathBuf::from("a.txt")));
+        assert_eq!(c.hard_link_to, None);
+        assert_eq!(c.size, 8);
+    }
+
     #[test]
     fn test_split_layers() {
         let mut planner = ConexPlanner::default();
@@ -166,7 +554,10 @@ mod tests {
             hard_link_to: Some(PathBuf::new()),
             ctime_nsec: 0,
             start_offset: None,
-            chunk_size: None
+            chunk_size: None,
+            content_chunks: None,
+            kind: FileKind::Regular,
+            xattrs: Vec::new(),
         });
         files.push(ConexFile {
             path: PathBuf::from("/var/lib/docker/overlay2/456"),
@@ -176,7 +567,10 @@ mod tests {
             hard_link_to: Some(PathBuf::new()),
             ctime_nsec: 0,
             start_offset: None,
-            chunk_size: None
+            chunk_size: None,
+            content_chunks: None,
+            kind: FileKind::Regular,
+            xattrs: Vec::new(),
         });
         files.push(ConexFile {
             path: PathBuf::from("/var/lib/docker/overlay2/789"),
@@ -186,7 +580,10 @@ mod tests {
             hard_link_to: Some(PathBuf::new()),
             ctime_nsec: 0,
             start_offset: None,
-            chunk_size: None
+            chunk_size: None,
+            content_chunks: None,
+            kind: FileKind::Regular,
+            xattrs: Vec::new(),
         });
         
 
@@ -223,7 +620,10 @@ mod tests {
             hard_link_to: Some(PathBuf::new()),
             ctime_nsec: 0,
             start_offset: None,
-            chunk_size: None
+            chunk_size: None,
+            content_chunks: None,
+            kind: FileKind::Regular,
+            xattrs: Vec::new(),
         });
         planner.layer_to_files.push(("/var/lib/docker/overlay2".to_owned(), files.clone()));
         planner.layer_to_files.push(("/var/lib/docker/overlay2".to_owned(), files.clone()));
@@ -253,7 +653,10 @@ mod tests {
             hard_link_to: Some(PathBuf::new()),
             ctime_nsec: 0,
             start_offset: None,
-            chunk_size: None
+            chunk_size: None,
+            content_chunks: None,
+            kind: FileKind::Regular,
+            xattrs: Vec::new(),
         });
         
         planner.layer_to_files.push(("/var/lib/docker/overlay2".to_owned(), files));
@@ -279,7 +682,10 @@ mod tests {
             hard_link_to: Some(PathBuf::new()),
             ctime_nsec: 0,
             start_offset: None,
-            chunk_size: None
+            chunk_size: None,
+            content_chunks: None,
+            kind: FileKind::Regular,
+            xattrs: Vec::new(),
         });
         
         planner.layer_to_files.push(("/var/lib/docker/overlay2".to_owned(), files.clone()));
@@ -295,6 +701,142 @@ mod tests {
         let mut c_files = t_files.clone();
         assert_eq!(c_files.pop().unwrap().chunk_size.unwrap(), 25);
         assert_eq!(c_files.pop().unwrap().size, 50);
-        
+
+    }
+
+    #[test]
+    fn best_fit_decreasing_packs_files_whole_without_splitting() {
+        let files = vec![
+            ConexFile {
+                path: PathBuf::from("/var/lib/docker/overlay2/60"),
+                relative_path: PathBuf::from("60"),
+                size: 60,
+                inode: 1,
+                hard_link_to: None,
+                ctime_nsec: 0,
+                start_offset: None,
+                chunk_size: None,
+                content_chunks: None,
+                kind: FileKind::Regular,
+                xattrs: Vec::new(),
+            },
+            ConexFile {
+                path: PathBuf::from("/var/lib/docker/overlay2/30"),
+                relative_path: PathBuf::from("30"),
+                size: 30,
+                inode: 2,
+                hard_link_to: None,
+                ctime_nsec: 0,
+                start_offset: None,
+                chunk_size: None,
+                content_chunks: None,
+                kind: FileKind::Regular,
+                xattrs: Vec::new(),
+            },
+            ConexFile {
+                path: PathBuf::from("/var/lib/docker/overlay2/40"),
+                relative_path: PathBuf::from("40"),
+                size: 40,
+                inode: 3,
+                hard_link_to: None,
+                ctime_nsec: 0,
+                start_offset: None,
+                chunk_size: None,
+                content_chunks: None,
+                kind: FileKind::Regular,
+                xattrs: Vec::new(),
+            },
+        ];
+
+        let mut planner = ConexPlanner::default();
+        planner.split_threshold = 100;
+        planner.pack_strategy = PackStrategy::BestFitDecreasing;
+        planner.layer_to_files.push(("/var/lib/docker/overlay2".to_owned(), files));
+
+        let plan = planner.generate_plan();
+        // 60 + 40 fit together (100), 30 opens its own layer: no file is
+        // split.
+        assert_eq!(plan.len(), 2, "plan is: {:?}", plan);
+        for (_, layer_files) in &plan {
+            for file in layer_files {
+                assert!(file.start_offset.is_none(), "file was split: {:?}", file);
+            }
+        }
+        let total_files: usize = plan.iter().map(|(_, files)| files.len()).sum();
+        assert_eq!(total_files, 3);
+    }
+
+    #[test]
+    fn best_fit_decreasing_keeps_hard_links_in_the_same_bin_as_their_target() {
+        let files = vec![
+            ConexFile {
+                path: PathBuf::from("/var/lib/docker/overlay2/a"),
+                relative_path: PathBuf::from("a"),
+                size: 10,
+                inode: 1,
+                hard_link_to: None,
+                ctime_nsec: 0,
+                start_offset: None,
+                chunk_size: None,
+                content_chunks: None,
+                kind: FileKind::Regular,
+                xattrs: Vec::new(),
+            },
+            ConexFile {
+                path: PathBuf::from("/var/lib/docker/overlay2/b"),
+                relative_path: PathBuf::from("b"),
+                size: 10,
+                inode: 1,
+                hard_link_to: None,
+                ctime_nsec: 0,
+                start_offset: None,
+                chunk_size: None,
+                content_chunks: None,
+                kind: FileKind::Regular,
+                xattrs: Vec::new(),
+            },
+        ];
+
+        let mut planner = ConexPlanner::default();
+        planner.split_threshold = 100;
+        planner.pack_strategy = PackStrategy::BestFitDecreasing;
+        planner.layer_to_files.push(("/var/lib/docker/overlay2".to_owned(), files));
+
+        let plan = planner.generate_plan();
+        assert_eq!(plan.len(), 1, "hard-linked files must share a bin: {:?}", plan);
+        assert_eq!(plan[0].1.len(), 2);
+    }
+
+    #[test]
+    fn best_fit_decreasing_treats_duplicate_chunks_as_zero_cost() {
+        let mut chunked = ConexFile {
+            path: PathBuf::from("/var/lib/docker/overlay2/a"),
+            relative_path: PathBuf::from("a"),
+            size: 100,
+            inode: 1,
+            hard_link_to: None,
+            ctime_nsec: 0,
+            start_offset: None,
+            chunk_size: None,
+            content_chunks: None,
+            kind: FileKind::Regular,
+            xattrs: Vec::new(),
+        };
+        chunked.content_chunks = Some(vec![
+            ConexChunk { start_offset: 0, chunk_size: 60, hash: blake3::hash(b"unique"), is_duplicate: false },
+            ConexChunk { start_offset: 60, chunk_size: 40, hash: blake3::hash(b"dup"), is_duplicate: true },
+        ]);
+
+        let mut planner = ConexPlanner::default();
+        planner.split_threshold = 60;
+        planner.pack_strategy = PackStrategy::BestFitDecreasing;
+        planner.layer_to_files.push(("/var/lib/docker/overlay2".to_owned(), vec![chunked]));
+
+        let plan = planner.generate_plan();
+        // Only the 60-byte unique chunk counts against the 60-byte
+        // threshold, so both chunks fit in a single bin even though their
+        // sizes sum to 100.
+        assert_eq!(plan.len(), 1, "plan is: {:?}", plan);
+        assert_eq!(plan[0].1.len(), 2);
     }
 }
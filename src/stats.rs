@@ -0,0 +1,199 @@
+//! Dedup/packing statistics over a generated plan.
+//!
+//! `plan_stats` reports, per layer and in aggregate, how much the planner's
+//! hard-link collapsing, fixed-offset splitting, and (once enabled) content
+//! chunking are actually buying a push.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crate::planner::ConexFile;
+
+/// Stats for a single layer (or, as `PlanStats::total`, the whole plan).
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct LayerStats {
+    pub layer: String,
+    pub total_bytes: u64,
+    pub file_count: usize,
+    /// Sum of `size` for every file whose `hard_link_to` is set; bytes the
+    /// hard-link pass avoided re-storing.
+    pub hard_link_bytes_reclaimed: u64,
+    /// Files that carry a `start_offset`/`chunk_size`, i.e. a fragment of a
+    /// file split across the `split_threshold` boundary or a single
+    /// content-defined chunk of a file.
+    pub fragment_count: usize,
+    /// Sum of content-defined chunk sizes seen in this layer.
+    pub logical_chunk_bytes: u64,
+    /// Sum of content-defined chunk sizes that were new (not a duplicate of
+    /// a chunk already seen elsewhere in the plan).
+    pub unique_chunk_bytes: u64,
+}
+
+impl LayerStats {
+    /// Unique chunk bytes over logical chunk bytes; `1.0` (no dedup yet
+    /// observed) when no file in this layer was content-chunked.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.logical_chunk_bytes == 0 {
+            1.0
+        } else {
+            self.unique_chunk_bytes as f64 / self.logical_chunk_bytes as f64
+        }
+    }
+
+    /// `generate_plan` emits one `ConexFile` per fragment, so a split or
+    /// content-chunked file shows up here several times with the same
+    /// `relative_path` and the same full `size`. Only the first fragment of
+    /// a given path contributes to `total_bytes`/`file_count`/
+    /// `hard_link_bytes_reclaimed`, which are logical-file stats; every
+    /// fragment still contributes to `fragment_count` and the chunk byte
+    /// totals, which are fragment-level stats.
+    fn add_file(&mut self, file: &ConexFile, seen_paths: &mut HashSet<PathBuf>) {
+        if seen_paths.insert(file.relative_path.clone()) {
+            self.total_bytes += file.size as u64;
+            self.file_count += 1;
+            if file.hard_link_to.is_some() {
+                self.hard_link_bytes_reclaimed += file.size as u64;
+            }
+        }
+        if file.start_offset.is_some() {
+            self.fragment_count += 1;
+        }
+        if let Some(chunks) = &file.content_chunks {
+            for chunk in chunks {
+                self.logical_chunk_bytes += chunk.chunk_size as u64;
+                if !chunk.is_duplicate {
+                    self.unique_chunk_bytes += chunk.chunk_size as u64;
+                }
+            }
+        }
+    }
+
+    fn merge_into(&self, total: &mut LayerStats) {
+        total.total_bytes += self.total_bytes;
+        total.file_count += self.file_count;
+        total.hard_link_bytes_reclaimed += self.hard_link_bytes_reclaimed;
+        total.fragment_count += self.fragment_count;
+        total.logical_chunk_bytes += self.logical_chunk_bytes;
+        total.unique_chunk_bytes += self.unique_chunk_bytes;
+    }
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize)]
+pub struct PlanStats {
+    pub layers: Vec<LayerStats>,
+    pub total: LayerStats,
+}
+
+/// Compute dedup/packing stats for `plan`, the output of
+/// `ConexPlanner::generate_plan`.
+pub fn plan_stats(plan: &[(String, Vec<ConexFile>)]) -> PlanStats {
+    let mut total = LayerStats { layer: "total".to_owned(), ..Default::default() };
+    // Shared across every layer (not reset per layer) since a single file's
+    // fragments can land in different layers, e.g. when a fixed-offset split
+    // straddles the `split_threshold` boundary.
+    let mut seen_paths = HashSet::new();
+    let layers = plan
+        .iter()
+        .map(|(name, files)| {
+            let mut layer_stats = LayerStats { layer: name.clone(), ..Default::default() };
+            for file in files {
+                layer_stats.add_file(file, &mut seen_paths);
+            }
+            layer_stats.merge_into(&mut total);
+            layer_stats
+        })
+        .collect();
+
+    PlanStats { layers, total }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::planner::{ConexChunk, FileKind};
+    use std::path::PathBuf;
+
+    fn file(relative_path: &str, size: usize, hard_link_to: Option<&str>) -> ConexFile {
+        ConexFile {
+            path: PathBuf::from(relative_path),
+            relative_path: PathBuf::from(relative_path),
+            size,
+            inode: 0,
+            hard_link_to: hard_link_to.map(PathBuf::from),
+            ctime_nsec: 0,
+            start_offset: None,
+            chunk_size: None,
+            content_chunks: None,
+            kind: FileKind::Regular,
+            xattrs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn hard_link_bytes_reclaimed_counts_only_linked_files() {
+        let plan = vec![(
+            "layer".to_owned(),
+            vec![file("a", 100, None), file("b", 100, Some("a"))],
+        )];
+
+        let stats = plan_stats(&plan);
+        assert_eq!(stats.total.hard_link_bytes_reclaimed, 100);
+        assert_eq!(stats.total.total_bytes, 200);
+        assert_eq!(stats.total.file_count, 2);
+    }
+
+    #[test]
+    fn fragment_count_counts_files_with_a_start_offset() {
+        let mut fragment = file("a", 50, None);
+        fragment.start_offset = Some(0);
+        fragment.chunk_size = Some(50);
+        let plan = vec![("layer".to_owned(), vec![fragment, file("b", 50, None)])];
+
+        let stats = plan_stats(&plan);
+        assert_eq!(stats.total.fragment_count, 1);
+    }
+
+    #[test]
+    fn dedup_ratio_reflects_duplicate_content_chunks() {
+        let mut chunked = file("a", 100, None);
+        chunked.content_chunks = Some(vec![
+            ConexChunk { start_offset: 0, chunk_size: 60, hash: blake3::hash(b"unique"), is_duplicate: false },
+            ConexChunk { start_offset: 60, chunk_size: 40, hash: blake3::hash(b"dup"), is_duplicate: true },
+        ]);
+        let plan = vec![("layer".to_owned(), vec![chunked])];
+
+        let stats = plan_stats(&plan);
+        assert_eq!(stats.total.logical_chunk_bytes, 100);
+        assert_eq!(stats.total.unique_chunk_bytes, 60);
+        assert_eq!(stats.total.dedup_ratio(), 0.6);
+    }
+
+    #[test]
+    fn dedup_ratio_is_one_without_content_chunking() {
+        let plan = vec![("layer".to_owned(), vec![file("a", 100, None)])];
+        let stats = plan_stats(&plan);
+        assert_eq!(stats.total.dedup_ratio(), 1.0);
+    }
+
+    #[test]
+    fn total_bytes_and_file_count_dedupe_fragments_of_the_same_file() {
+        let mut fragment_a = file("a", 50, None);
+        fragment_a.start_offset = Some(0);
+        fragment_a.chunk_size = Some(25);
+        fragment_a.content_chunks =
+            Some(vec![ConexChunk { start_offset: 0, chunk_size: 25, hash: blake3::hash(b"x"), is_duplicate: false }]);
+
+        let mut fragment_b = file("a", 50, None);
+        fragment_b.start_offset = Some(25);
+        fragment_b.chunk_size = Some(25);
+        fragment_b.content_chunks =
+            Some(vec![ConexChunk { start_offset: 25, chunk_size: 25, hash: blake3::hash(b"y"), is_duplicate: false }]);
+
+        let plan = vec![("layer".to_owned(), vec![fragment_a, fragment_b])];
+
+        let stats = plan_stats(&plan);
+        assert_eq!(stats.total.total_bytes, 50);
+        assert_eq!(stats.total.file_count, 1);
+        assert_eq!(stats.total.fragment_count, 2);
+    }
+}